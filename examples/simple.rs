@@ -2,7 +2,7 @@ extern crate qrwlock;
 use std::{sync::Arc, thread};
 
 fn main() {
-    let counter = Arc::new(qrwlock::RwLock::new(0));
+    let counter = Arc::new(qrwlock::RwLock::<i32>::new(0));
 
     let thread = thread::spawn({
         let counter = counter.clone();
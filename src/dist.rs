@@ -0,0 +1,232 @@
+//! A sharded, NUMA-friendly reader-writer lock.
+//!
+//! [`RwLock`](crate::qrwlock::RwLock) funnels every reader through a single `AtomicU32` counter,
+//! which cache-line-bounces badly once many threads are reading concurrently on a multi-socket
+//! machine. [`DistRwLock`] instead keeps an array of cache-padded reader counters, indexed by a
+//! hash of the thread id: a reader only ever writes to its thread's slot, while a writer still
+//! goes through the existing `qrwlock` word, so writers keep the crate's FIFO fairness guarantees.
+
+extern crate std;
+
+use core::{
+    cell::UnsafeCell,
+    hash::{Hash, Hasher},
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use std::{collections::hash_map::DefaultHasher, thread};
+
+use spin::{relax::Spin, RelaxStrategy};
+
+use crate::qrwlock::{RwLock, WriteGuard};
+
+/// Number of independent reader slots. Threads whose id hashes to the same slot simply share a
+/// counter, so this only needs to be large enough that collisions are rare under expected
+/// concurrency, not one slot per thread that will ever run.
+pub const MAX_READER_THREADS: usize = 64;
+
+/// A value padded out to a cache line, so that neighbouring reader slots never false-share.
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+fn slot_for_current_thread() -> usize {
+    let mut hasher = DefaultHasher::new();
+    thread::current().id().hash(&mut hasher);
+    (hasher.finish() as usize) % MAX_READER_THREADS
+}
+
+/// A [read-write lock](https://en.wikipedia.org/wiki/Readers%E2%80%93writer_lock) that scales
+/// reads across cores by spreading reader counts over cache-padded slots, chosen by hashing the
+/// reader's thread id, instead of a single shared counter. Threads whose ids hash to the same
+/// slot share it.
+///
+/// Writer acquisition reuses [`RwLock`](crate::qrwlock::RwLock)'s FIFO ticket queue, so writers
+/// are served in the same fair order as the rest of the crate; only the reader fast path is
+/// sharded.
+///
+/// Like [`RwLock`](crate::qrwlock::RwLock), the backoff strategy used while spinning is
+/// parameterized by `R`, defaulting to [`Spin`].
+pub struct DistRwLock<T, R = Spin> {
+    writer: RwLock<(), R>,
+    slots: [CachePadded<AtomicUsize>; MAX_READER_THREADS],
+    data: UnsafeCell<T>,
+}
+
+/// Guard that provides read-only access to the data protected by a [`DistRwLock`]
+pub struct DistReadGuard<'a, T: 'a, R = Spin> {
+    lock: &'a DistRwLock<T, R>,
+    slot: usize,
+    data: &'a T,
+}
+
+/// Guard that provides exclusive read-write access to the data protected by a [`DistRwLock`]
+pub struct DistWriteGuard<'a, T: 'a, R = Spin> {
+    _guard: WriteGuard<'a, (), R>,
+    data: &'a mut T,
+}
+
+impl<T, R> DistRwLock<T, R> {
+    /// Creates a new lock wrapping passed data
+    pub fn new(data: T) -> Self {
+        Self {
+            writer: RwLock::new(()),
+            slots: core::array::from_fn(|_| CachePadded(AtomicUsize::new(0))),
+            data: UnsafeCell::new(data),
+        }
+    }
+}
+
+impl<T, R: RelaxStrategy> DistRwLock<T, R> {
+    /// Acquire the lock for read
+    ///
+    /// Bumps only the calling thread's slot, then backs off and retries if a writer is holding or
+    /// waiting for the lock, like the fast path of `RwLock::read`. A reader that backs off spins
+    /// until no writer is pending; it does not queue on the writers' ticket queue.
+    ///
+    /// The slot increment and the writer-pending check both use `SeqCst`: Acquire/Release alone
+    /// permits the StoreLoad reordering that would let this store and the writer's slot load (see
+    /// [`DistRwLock::write`]) cross each other on weakly-ordered hardware, letting a reader and a
+    /// writer both proceed concurrently.
+    pub fn read(&self) -> DistReadGuard<T, R> {
+        let slot = slot_for_current_thread();
+
+        loop {
+            self.slots[slot].fetch_add(1, Ordering::SeqCst);
+
+            if !self.writer.writer_pending(Ordering::SeqCst) {
+                break;
+            }
+
+            // A writer is pending, don't hold up its drain loop with our slot
+            self.slots[slot].fetch_sub(1, Ordering::Relaxed);
+            R::relax();
+        }
+
+        DistReadGuard {
+            lock: self,
+            slot,
+            data: unsafe { &*self.data.get() },
+        }
+    }
+
+    /// Acquire the lock for write
+    ///
+    /// Claims exclusivity through the inner `qrwlock` word first, then spins until every reader
+    /// slot has drained to zero. The slot load uses `SeqCst` to pair with the `SeqCst` slot
+    /// increment in [`DistRwLock::read`]; see its doc comment.
+    ///
+    /// `RwLock::write`'s own CAS that claims the word is only `Acquire`, so it doesn't itself join
+    /// the single total SeqCst order the read-side relies on. An explicit `SeqCst` fence here
+    /// closes that gap before the drain loop runs.
+    pub fn write(&self) -> DistWriteGuard<T, R> {
+        #[cfg(not(feature = "poison"))]
+        let guard = self.writer.write();
+        #[cfg(feature = "poison")]
+        let guard = self
+            .writer
+            .write()
+            .unwrap_or_else(crate::poison::PoisonError::into_inner);
+
+        core::sync::atomic::fence(Ordering::SeqCst);
+
+        for slot in self.slots.iter() {
+            while slot.load(Ordering::SeqCst) != 0 {
+                R::relax();
+            }
+        }
+
+        DistWriteGuard {
+            _guard: guard,
+            data: unsafe { &mut *self.data.get() },
+        }
+    }
+}
+
+impl<'a, T, R> Drop for DistReadGuard<'a, T, R> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        self.lock.slots[self.slot].fetch_sub(1, Ordering::Release);
+    }
+}
+
+impl<'a, T, R> Deref for DistReadGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, R> Deref for DistWriteGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, R> DerefMut for DistWriteGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
+
+unsafe impl<T, R> Sync for DistRwLock<T, R> {}
+unsafe impl<T, R> Send for DistRwLock<T, R> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::vec::Vec;
+
+    #[test]
+    fn dist_rwlock_test_single_threaded() {
+        let lock = DistRwLock::<i32>::new(0);
+
+        let mut locked = lock.write();
+        *locked += 1;
+        drop(locked);
+
+        let r1 = lock.read();
+        let r2 = lock.read();
+        assert_eq!(*r1, 1);
+        assert_eq!(*r2, 1);
+    }
+
+    #[test]
+    fn dist_rwlock_test_multi_threaded() {
+        const READ_NUM_THREADS: usize = 10;
+
+        let lock = Arc::new(DistRwLock::<usize>::new(0usize));
+
+        {
+            let mut locked = lock.write();
+            *locked = 42;
+        }
+
+        let ths: Vec<_> = (0..READ_NUM_THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        assert_eq!(*lock.read(), 42);
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect();
+
+        for th in ths {
+            th.join().unwrap();
+        }
+    }
+}
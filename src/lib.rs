@@ -1,12 +1,21 @@
 #![no_std]
 
-#[cfg(test)]
+#[cfg(any(test, feature = "poison"))]
 extern crate std;
 
 extern crate static_assertions;
 
 pub mod qrwlock;
 
+/// Sharded/NUMA-friendly [`DistRwLock`](dist::DistRwLock), with one reader counter per thread
+/// slot instead of a single shared counter. Needs `std` for thread identification.
+#[cfg(feature = "sharded")]
+pub mod dist;
+
+/// Opt-in lock poisoning, mirroring `std::sync::RwLock`. Needs `std` to detect panics.
+#[cfg(feature = "poison")]
+pub mod poison;
+
 #[cfg(test)]
 mod test {
     use super::qrwlock::*;
@@ -17,17 +26,30 @@ mod test {
     use std::vec::Vec;
     use std::sync::atomic::{Ordering, AtomicU32};
 
+    /// Unwraps whatever `read()`/`write()` hand back, regardless of whether the `poison` feature
+    /// turns them into a `LockResult`, so the bulk of the tests don't need to be duplicated or
+    /// disabled per feature combination.
+    #[cfg(feature = "poison")]
+    fn unwrap_guard<G>(result: super::poison::LockResult<G>) -> G {
+        result.unwrap()
+    }
+
+    #[cfg(not(feature = "poison"))]
+    fn unwrap_guard<G>(guard: G) -> G {
+        guard
+    }
+
     #[test]
     fn qrwlock_test_single_threaded() {
-        let lock = RwLock::new(());
+        let lock = RwLock::<()>::new(());
 
-        let locked = lock.write();
+        let locked = unwrap_guard(lock.write());
         assert!(lock.read_try_lock().is_none());
         assert!(lock.write_try_lock().is_none());
         drop(locked);
 
-        let _locked1 = lock.read();
-        let _locked2 = lock.read();
+        let _locked1 = unwrap_guard(lock.read());
+        let _locked2 = unwrap_guard(lock.read());
 
         assert!(lock.write_try_lock().is_none());
     }
@@ -38,7 +60,7 @@ mod test {
         const WRITE_NUM_THREADS: usize = 2;
         const WRITER: u32 = 1 << 31;
 
-        let lock = Arc::new(RwLock::new(AtomicU32::new(0)));
+        let lock = Arc::new(RwLock::<AtomicU32>::new(AtomicU32::new(0)));
 
         let r_ths: Vec<_> = (0..READ_NUM_THREADS)
             .map(|_| {
@@ -47,7 +69,7 @@ mod test {
                     let mut rng = rand::thread_rng();
 
                     for _ in 0..100 {
-                        let locked = lock.read();
+                        let locked = unwrap_guard(lock.read());
                         assert!((*locked).load(Ordering::Relaxed) & WRITER == 0);
 
                         (*locked).fetch_add(1, Ordering::Relaxed);
@@ -69,7 +91,7 @@ mod test {
                     let mut rng = rand::thread_rng();
 
                     for _ in 0..100 {
-                        let locked = lock.write();
+                        let locked = unwrap_guard(lock.write());
 
                         assert!((*locked).compare_exchange(0, WRITER, Ordering::Relaxed, Ordering::Relaxed).is_ok());
                         thread::sleep(Duration::from_millis(rng.gen_range(10..50)));
@@ -91,4 +113,50 @@ mod test {
             th.join().unwrap();
         }
     }
+
+    #[test]
+    fn qrwlock_test_mapped_guards() {
+        let lock = RwLock::<(i32, i32)>::new((1, 2));
+
+        {
+            let mapped = unwrap_guard(lock.read()).map(|pair| &pair.0);
+            assert_eq!(*mapped, 1);
+        }
+
+        {
+            let mut mapped = unwrap_guard(lock.write()).map(|pair| &mut pair.1);
+            *mapped += 1;
+        }
+
+        assert_eq!(*unwrap_guard(lock.read()), (1, 3));
+    }
+
+    #[cfg(feature = "poison")]
+    #[test]
+    fn qrwlock_test_poison_on_panic() {
+        let lock = Arc::new(RwLock::<i32>::new(0));
+
+        let result = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let _guard = lock.write().unwrap();
+                panic!("simulated failure while holding the write lock");
+            })
+            .join()
+        };
+
+        assert!(result.is_err());
+        assert!(lock.is_poisoned());
+
+        match lock.read() {
+            Ok(_) => panic!("expected the lock to be poisoned"),
+            Err(err) => {
+                let _guard = err.into_inner();
+            }
+        }
+
+        lock.clear_poison();
+        assert!(!lock.is_poisoned());
+        assert!(lock.read().is_ok());
+    }
 }
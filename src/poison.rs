@@ -0,0 +1,53 @@
+//! Optional poisoning support, mirroring `std::sync::RwLock`'s `LockResult`/`PoisonError` API.
+//!
+//! Gated behind the `poison` feature: when enabled, a thread panicking while holding a
+//! [`WriteGuard`](crate::qrwlock::WriteGuard) marks the lock poisoned, and subsequent
+//! [`read`](crate::qrwlock::RwLock::read)/[`write`](crate::qrwlock::RwLock::write) calls return
+//! a [`LockResult`] instead of silently handing out possibly-corrupt data.
+
+use core::fmt;
+
+/// The result of a lock method which can fail due to poisoning
+pub type LockResult<Guard> = Result<Guard, PoisonError<Guard>>;
+
+/// Wraps the guard normally returned by a successful lock acquisition, signalling that the lock
+/// had previously been poisoned by a thread panicking while holding it.
+///
+/// The underlying guard can still be recovered via [`into_inner`](PoisonError::into_inner), since
+/// the data it protects is not necessarily corrupt.
+pub struct PoisonError<Guard> {
+    guard: Guard,
+}
+
+impl<Guard> PoisonError<Guard> {
+    pub(crate) fn new(guard: Guard) -> Self {
+        Self { guard }
+    }
+
+    /// Consumes this error, returning the underlying guard.
+    pub fn into_inner(self) -> Guard {
+        self.guard
+    }
+
+    /// Returns a reference to the underlying guard.
+    pub fn get_ref(&self) -> &Guard {
+        &self.guard
+    }
+
+    /// Returns a mutable reference to the underlying guard.
+    pub fn get_mut(&mut self) -> &mut Guard {
+        &mut self.guard
+    }
+}
+
+impl<Guard> fmt::Debug for PoisonError<Guard> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PoisonError").finish_non_exhaustive()
+    }
+}
+
+impl<Guard> fmt::Display for PoisonError<Guard> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("poisoned lock: another thread failed while holding the write guard")
+    }
+}
@@ -1,17 +1,24 @@
-//! A fair rwlock. Enspired by [qrwlock from linux](https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/tree/include/asm-generic/qrwlock.h) 
+//! A fair rwlock. Enspired by [qrwlock from linux](https://git.kernel.org/pub/scm/linux/kernel/git/torvalds/linux.git/tree/include/asm-generic/qrwlock.h)
 
 use core::{
     cell::UnsafeCell,
-    mem::ManuallyDrop,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
     ops::{Deref, DerefMut},
     sync::atomic::{AtomicU32, AtomicU8, Ordering},
 };
 use spin::{mutex::TicketMutex, relax::Spin, RelaxStrategy};
 
+#[cfg(feature = "poison")]
+use core::sync::atomic::AtomicBool;
+#[cfg(feature = "poison")]
+use crate::poison::{LockResult, PoisonError};
+
 const WRITER_LOCKED: u32 = 0xff;
 const WRITER_WAITING: u32 = 1 << 8;
 const WRITER_MASK: u32 = WRITER_LOCKED | WRITER_WAITING;
-const READER_COUNT: u32 = 1 << 9;
+const UPGRADABLE_READER: u32 = 1 << 9;
+const READER_COUNT: u32 = 1 << 10;
 
 #[cfg(target_endian = "big")]
 #[repr(C)]
@@ -51,26 +58,63 @@ static_assertions::const_assert!(core::mem::size_of::<RawRwlock>() == core::mem:
 /// Qrwlock solves unfairness by serializing lock request with FIFO waitqueue based on
 /// ticket spinlock. That means if writer was placed into waitqueue before a reader,
 /// its lock request would be served earlier.
-pub struct RwLock<T> {
+///
+/// The `R` type parameter picks the backoff strategy used while spinning on contention, and
+/// defaults to [`Spin`], a pure busy spin. Plug in `spin::relax::Loop`, a yielding strategy, or
+/// your own [`RelaxStrategy`] impl if a tight busy spin isn't appropriate, e.g. in a userspace
+/// server sharing cores with other work.
+pub struct RwLock<T, R = Spin> {
     raw: RawRwlock,
     data: UnsafeCell<T>,
     wq: TicketMutex<()>,
+    _relax: PhantomData<R>,
+    #[cfg(feature = "poison")]
+    poisoned: AtomicBool,
 }
 
 /// Guard that provides read-only access to underlying data
-pub struct ReadGuard<'a, T: 'a> {
-    lock: &'a RwLock<T>,
+pub struct ReadGuard<'a, T: 'a, R = Spin> {
+    lock: &'a RwLock<T, R>,
     data: &'a T,
 }
 
 /// Guard that provides read-rite access to underlying data
 /// WriteGuard<T> guarantees exclusive access.
-pub struct WriteGuard<'a, T: 'a> {
-    lock: &'a RwLock<T>,
+pub struct WriteGuard<'a, T: 'a, R = Spin> {
+    lock: &'a RwLock<T, R>,
     data: &'a mut T,
 }
 
-impl<T> RwLock<T> {
+/// A read guard that has been projected down to a sub-field of `T` via [`ReadGuard::map`].
+///
+/// Holds the read lock for as long as a plain [`ReadGuard`] would, but `Deref`s to `U` instead
+/// of `T`.
+pub struct MappedReadGuard<'a, T: 'a, U: 'a, R = Spin> {
+    lock: &'a RwLock<T, R>,
+    data: &'a U,
+}
+
+/// A write guard that has been projected down to a sub-field of `T` via [`WriteGuard::map`].
+///
+/// Holds the write lock for as long as a plain [`WriteGuard`] would, but `Deref`/`DerefMut`s to
+/// `U` instead of `T`.
+pub struct MappedWriteGuard<'a, T: 'a, U: 'a, R = Spin> {
+    lock: &'a RwLock<T, R>,
+    data: &'a mut U,
+}
+
+/// Guard that provides read-only access to underlying data, with the ability to be atomically
+/// promoted to a [`WriteGuard`] via [`UpgradableReadGuard::upgrade`] or
+/// [`UpgradableReadGuard::try_upgrade`].
+///
+/// Only one `UpgradableReadGuard` may be held at a time, but it does not block plain [`read`](RwLock::read)
+/// accesses from other threads, only writers.
+pub struct UpgradableReadGuard<'a, T: 'a, R = Spin> {
+    lock: &'a RwLock<T, R>,
+    data: &'a T,
+}
+
+impl<T, R> RwLock<T, R> {
     /// Creates a new rwlock wrapping passed data
     #[inline]
     pub fn new(data: T) -> Self {
@@ -78,6 +122,9 @@ impl<T> RwLock<T> {
             wq: TicketMutex::new(()),
             raw: unsafe { core::mem::zeroed() },
             data: UnsafeCell::new(data),
+            _relax: PhantomData,
+            #[cfg(feature = "poison")]
+            poisoned: AtomicBool::new(false),
         }
     }
 
@@ -85,7 +132,7 @@ impl<T> RwLock<T> {
     ///
     /// Returns Some(WriteGuard<T>) if lock was acquired, None otherwise
     #[inline(always)]
-    pub fn write_try_lock(&self) -> Option<WriteGuard<T>> {
+    pub fn write_try_lock(&self) -> Option<WriteGuard<T, R>> {
         let raw = self.raw(Ordering::Relaxed);
 
         if raw == 0
@@ -109,7 +156,7 @@ impl<T> RwLock<T> {
     ///
     /// Returns Some(ReadGuard<T>) if lock was acquired, None otherwise
     #[inline(always)]
-    pub fn read_try_lock(&self) -> Option<ReadGuard<T>> {
+    pub fn read_try_lock(&self) -> Option<ReadGuard<T, R>> {
         let mut raw = self.raw(Ordering::Relaxed);
 
         if raw & WRITER_MASK == 0 {
@@ -127,22 +174,17 @@ impl<T> RwLock<T> {
         }
     }
 
-    fn wait_for_writes_to_unlock(&self) {
-        loop {
-            let cur = self.raw(Ordering::Acquire);
-
-            if cur & WRITER_MASK == 0 {
-                break;
-            }
-
-            Spin::relax();
-        }
-    }
-
     pub(crate) fn raw(&self, order: Ordering) -> u32 {
         unsafe { self.raw.bits.load(order) }
     }
 
+    /// Whether a writer currently holds or is waiting for the lock.
+    #[cfg(feature = "sharded")]
+    #[inline(always)]
+    pub(crate) fn writer_pending(&self, order: Ordering) -> bool {
+        self.raw(order) & WRITER_MASK != 0
+    }
+
     #[inline(always)]
     fn add_read_count(&self, order: Ordering) -> u32 {
         unsafe { self.raw.bits.fetch_add(READER_COUNT, order) }
@@ -176,6 +218,53 @@ impl<T> RwLock<T> {
         }
     }
 
+    #[inline(always)]
+    pub(crate) fn write_unlock(&self) {
+        unsafe {
+            self.raw.raw.w_lock.store(0, Ordering::Release)
+        };
+    }
+
+    #[inline(always)]
+    pub(crate) fn clear_upgradable(&self, order: Ordering) -> u32 {
+        unsafe { self.raw.bits.fetch_and(!UPGRADABLE_READER, order) }
+    }
+}
+
+#[cfg(feature = "poison")]
+impl<T, R> RwLock<T, R> {
+    /// Whether a thread has panicked while holding the [`WriteGuard`].
+    #[inline(always)]
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// Clears the poisoned state of this lock, allowing subsequent `read`/`write` calls to
+    /// succeed as if the lock had never been poisoned.
+    #[inline(always)]
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub(crate) fn poisoned(&self) -> &AtomicBool {
+        &self.poisoned
+    }
+}
+
+impl<T, R: RelaxStrategy> RwLock<T, R> {
+    fn wait_for_writes_to_unlock(&self) {
+        loop {
+            let cur = self.raw(Ordering::Acquire);
+
+            if cur & WRITER_MASK == 0 {
+                break;
+            }
+
+            R::relax();
+        }
+    }
+
     fn read_lock_slow(&self) {
         // Imaginary value to force drop at the end of the function
         let _guard = self.wq.lock();
@@ -214,18 +303,12 @@ impl<T> RwLock<T> {
                 return;
             }
 
-            Spin::relax();
+            R::relax();
         }
     }
 
-    /// Acquire the lock for read
-    ///
-    /// Returns ReadGuard<T>
-    ///
-    /// If lock is locked for readers than only readers may access the underlying data
-    /// This function is divided into fast and slow path. Fast path is inlined, slow path is not
     #[inline(always)]
-    pub fn read(&self) -> ReadGuard<T> {
+    fn read_impl(&self) -> ReadGuard<T, R> {
         if !self.read_lock_fast() {
             self.read_lock_slow();
         }
@@ -236,66 +319,333 @@ impl<T> RwLock<T> {
         }
     }
 
+    #[inline(always)]
+    fn write_impl(&self) -> WriteGuard<T, R> {
+        if !self.write_lock_fast() {
+            self.write_lock_slow();
+        }
+
+        WriteGuard {
+            lock: &self,
+            data: unsafe { &mut *self.data.get() },
+        }
+    }
+
+    /// Acquire the lock for read
+    ///
+    /// Returns ReadGuard<T>
+    ///
+    /// If lock is locked for readers than only readers may access the underlying data
+    /// This function is divided into fast and slow path. Fast path is inlined, slow path is not
+    #[cfg(not(feature = "poison"))]
+    #[inline(always)]
+    pub fn read(&self) -> ReadGuard<T, R> {
+        self.read_impl()
+    }
+
     /// Acquire the lock for write
     ///
     /// Returns WriteGuard<T>
     ///
     /// This function is divided into fast and slow path. Fast path is inlined, slow path is not
+    #[cfg(not(feature = "poison"))]
     #[inline(always)]
-    pub fn write(&self) -> WriteGuard<T> {
-        if !self.write_lock_fast() {
-            self.write_lock_slow();
+    pub fn write(&self) -> WriteGuard<T, R> {
+        self.write_impl()
+    }
+
+    /// Acquire the lock for read
+    ///
+    /// Returns `Err` if the lock is poisoned, i.e. a thread previously panicked while holding
+    /// the [`WriteGuard`]; the guard is still reachable through [`PoisonError::into_inner`].
+    #[cfg(feature = "poison")]
+    #[inline(always)]
+    pub fn read(&self) -> LockResult<ReadGuard<T, R>> {
+        let guard = self.read_impl();
+
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// Acquire the lock for write
+    ///
+    /// Returns `Err` if the lock is poisoned, i.e. a thread previously panicked while holding
+    /// the [`WriteGuard`]; the guard is still reachable through [`PoisonError::into_inner`].
+    #[cfg(feature = "poison")]
+    #[inline(always)]
+    pub fn write(&self) -> LockResult<WriteGuard<T, R>> {
+        let guard = self.write_impl();
+
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
         }
+    }
 
-        WriteGuard {
+    fn read_upgradable_lock_slow(&self) {
+        // Only one upgradable reader is permitted at a time. Wait for the current one without
+        // holding wq, since its upgrade() needs wq to complete.
+        loop {
+            let guard = self.wq.lock();
+
+            let prev = unsafe { self.raw.bits.fetch_or(UPGRADABLE_READER, Ordering::Acquire) };
+
+            if prev & UPGRADABLE_READER == 0 {
+                // Behaves like a regular reader for the purpose of writer exclusion
+                self.add_read_count(Ordering::Relaxed);
+                self.wait_for_writes_to_unlock();
+                return;
+            }
+
+            drop(guard);
+
+            while self.raw(Ordering::Relaxed) & UPGRADABLE_READER != 0 {
+                R::relax();
+            }
+        }
+    }
+
+    /// Acquire the lock for read, allowing the returned guard to later be upgraded to a
+    /// [`WriteGuard`] via [`UpgradableReadGuard::upgrade`].
+    ///
+    /// Only one upgradable reader is permitted at a time; a second call blocks until the first
+    /// one is dropped or upgraded. Like [`read`](RwLock::read), this still allows concurrent
+    /// plain readers, but blocks writers.
+    #[inline(always)]
+    pub fn read_upgradable(&self) -> UpgradableReadGuard<T, R> {
+        self.read_upgradable_lock_slow();
+
+        UpgradableReadGuard {
             lock: &self,
-            data: unsafe { &mut *self.data.get() },
+            data: unsafe { &*self.data.get() },
         }
     }
+}
 
+impl<'a, T, R> Drop for ReadGuard<'a, T, R> {
     #[inline(always)]
-    pub(crate) fn write_unlock(&self) {
+    fn drop(&mut self) {
+        self.lock.sub_read_count(Ordering::Release);
+    }
+}
+
+impl<'a, T, R> ReadGuard<'a, T, R> {
+    /// Projects this guard down to a sub-field of `T`, returning a [`MappedReadGuard`] that
+    /// `Deref`s to `U` while continuing to hold the original read lock.
+    pub fn map<U, F>(self, f: F) -> MappedReadGuard<'a, T, U, R>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        let lock = self.lock;
+        let data = f(unsafe { &*lock.data.get() });
+        mem::forget(self);
+
+        MappedReadGuard { lock, data }
+    }
+}
+
+impl<'a, T, R> Drop for WriteGuard<'a, T, R> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        #[cfg(feature = "poison")]
+        if std::thread::panicking() {
+            self.lock.poisoned().store(true, Ordering::Release);
+        }
+
+        self.lock.write_unlock();
+    }
+}
+
+impl<'a, T, R> WriteGuard<'a, T, R> {
+    /// Atomically downgrades this exclusive write hold into a shared [`ReadGuard`], without ever
+    /// fully releasing the lock.
+    ///
+    /// This means no other writer can acquire the lock between the downgrade and the first
+    /// subsequent read, unlike dropping the `WriteGuard` and calling [`RwLock::read`] separately.
+    pub fn downgrade(self) -> ReadGuard<'a, T, R> {
+        let lock = self.lock;
+        mem::forget(self);
+
         unsafe {
-            self.raw.raw.w_lock.store(0, Ordering::Release)
+            lock.raw
+                .bits
+                .fetch_add(READER_COUNT.wrapping_sub(WRITER_LOCKED), Ordering::Release)
         };
+
+        ReadGuard {
+            lock,
+            data: unsafe { &*lock.data.get() },
+        }
+    }
+
+    /// Projects this guard down to a sub-field of `T`, returning a [`MappedWriteGuard`] that
+    /// `Deref`/`DerefMut`s to `U` while continuing to hold the original write lock.
+    pub fn map<U, F>(self, f: F) -> MappedWriteGuard<'a, T, U, R>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        let lock = self.lock;
+        let data = f(unsafe { &mut *lock.data.get() });
+        mem::forget(self);
+
+        MappedWriteGuard { lock, data }
+    }
+}
+
+impl<'a, T, R> Drop for UpgradableReadGuard<'a, T, R> {
+    #[inline(always)]
+    fn drop(&mut self) {
+        self.lock.sub_read_count(Ordering::Relaxed);
+        self.lock.clear_upgradable(Ordering::Release);
+    }
+}
+
+impl<'a, T, R: RelaxStrategy> UpgradableReadGuard<'a, T, R> {
+    /// Atomically promotes this upgradable read guard into a [`WriteGuard`], blocking until all
+    /// other readers have released the lock.
+    ///
+    /// No writer can acquire the lock in the meantime, since an upgradable reader is held for
+    /// the entire duration.
+    pub fn upgrade(self) -> WriteGuard<'a, T, R> {
+        let lock = self.lock;
+        mem::forget(self);
+
+        // Hold wq across the whole drain loop, same as write_lock_slow: otherwise a plain read()
+        // arriving after WRITER_WAITING is set would find wq free, take read_lock_slow, and park
+        // in wait_for_writes_to_unlock while still holding its reader count, deadlocking against
+        // this loop waiting for that same reader count to drain.
+        let _guard = lock.wq.lock();
+
+        // A plain writer may have already set WRITER_WAITING via a fetch_or of its own; fetch_add
+        // would carry into UPGRADABLE_READER in that case, so set the bit idempotently.
+        unsafe { lock.raw.bits.fetch_or(WRITER_WAITING, Ordering::Relaxed) };
+        lock.sub_read_count(Ordering::Relaxed);
+
+        loop {
+            let raw = lock.raw(Ordering::Relaxed);
+
+            if raw & !(WRITER_WAITING | UPGRADABLE_READER) == 0
+                && unsafe {
+                    lock.raw
+                        .bits
+                        .compare_exchange(raw, WRITER_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                        .is_ok()
+                }
+            {
+                break;
+            }
+
+            R::relax();
+        }
+
+        WriteGuard {
+            lock,
+            data: unsafe { &mut *lock.data.get() },
+        }
+    }
+
+    /// Tries to atomically promote this upgradable read guard into a [`WriteGuard`] without
+    /// blocking.
+    ///
+    /// Returns `Err(self)` if other readers are still holding the lock.
+    pub fn try_upgrade(self) -> Result<WriteGuard<'a, T, R>, Self> {
+        let lock = self.lock;
+        let raw = lock.raw(Ordering::Relaxed);
+
+        if raw & !UPGRADABLE_READER == READER_COUNT
+            && unsafe {
+                lock.raw
+                    .bits
+                    .compare_exchange(raw, WRITER_LOCKED, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            }
+        {
+            mem::forget(self);
+
+            Ok(WriteGuard {
+                lock,
+                data: unsafe { &mut *lock.data.get() },
+            })
+        } else {
+            Err(self)
+        }
     }
 }
 
-impl<'a, T> Drop for ReadGuard<'a, T> {
+impl<'a, T, R> Deref for ReadGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, R> Deref for WriteGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, R> Deref for UpgradableReadGuard<'a, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, R> DerefMut for WriteGuard<'a, T, R> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.data
+    }
+}
+
+impl<'a, T, U, R> Drop for MappedReadGuard<'a, T, U, R> {
     #[inline(always)]
     fn drop(&mut self) {
         self.lock.sub_read_count(Ordering::Release);
     }
 }
 
-impl<'a, T> Drop for WriteGuard<'a, T> {
+impl<'a, T, U, R> Drop for MappedWriteGuard<'a, T, U, R> {
     #[inline(always)]
     fn drop(&mut self) {
+        #[cfg(feature = "poison")]
+        if std::thread::panicking() {
+            self.lock.poisoned().store(true, Ordering::Release);
+        }
+
         self.lock.write_unlock();
     }
 }
 
-impl<'a, T> Deref for ReadGuard<'a, T> {
-    type Target = T;
+impl<'a, T, U, R> Deref for MappedReadGuard<'a, T, U, R> {
+    type Target = U;
 
     fn deref(&self) -> &Self::Target {
         self.data
     }
 }
 
-impl<'a, T> Deref for WriteGuard<'a, T> {
-    type Target = T;
+impl<'a, T, U, R> Deref for MappedWriteGuard<'a, T, U, R> {
+    type Target = U;
 
     fn deref(&self) -> &Self::Target {
         self.data
     }
 }
 
-impl<'a, T> DerefMut for WriteGuard<'a, T> {
+impl<'a, T, U, R> DerefMut for MappedWriteGuard<'a, T, U, R> {
     fn deref_mut(&mut self) -> &mut Self::Target {
         self.data
     }
 }
 
-unsafe impl<T> Sync for RwLock<T> {}
-unsafe impl<T> Send for RwLock<T> {}
+unsafe impl<T, R> Sync for RwLock<T, R> {}
+unsafe impl<T, R> Send for RwLock<T, R> {}